@@ -2,40 +2,1602 @@
 """
 sense.py: Monitoring environment with Sense HAT.
 """
+import os
+import sys
+import math
+import fcntl
 import time
+import glob
+import ctypes
+import random
+import shutil
+import signal
+import logging
+import zoneinfo
+import subprocess
+import json
 import argparse
-from sense_hat import SenseHat
+import datetime
+import contextlib
+
+try:
+    from sense_hat import SenseHat
+except ImportError:
+    # --simulate works without sense_hat
+    SenseHat = None
+
+# RTIMULib runs HTS221 at 12.5 Hz and LPS25H at 25 Hz. Waiting a little longer
+# than one HTS221 period makes every sample in a burst a fresh conversion.
+SAMPLE_DELAY = 0.1
+
+# log level for every raw sensor value
+TRACE = 5
+logging.addLevelName(TRACE, "TRACE")
+
+# log levels selected by no, one, and two -v
+VERBOSITY = (logging.INFO, logging.DEBUG, TRACE)
+
+# SoC temperature in millidegrees Celsius
+THERMAL_ZONE = "/sys/class/thermal/thermal_zone0/temp"
+
+# I2C bus of the Sense HAT
+I2C_BUS = "/dev/i2c-1"
+
+# framebuffer name of the Sense HAT LED matrix, which sense_hat requires
+FRAMEBUFFER_NAME = "RPi-Sense FB"
+
+# free space --doctor expects next to records.tsv, about ten years of records
+MINIMUM_FREE_SPACE = 100 * 1024 * 1024
+
+# plausible range of each channel for --self-test. Temperatures are limited to
+# the operating ranges of LPS25H and HTS221.
+SELF_TEST_BOUNDS = {
+    "pressure": (300, 1100),
+    "temperature_p": (-30, 105),
+    "humidity": (0, 100),
+    "temperature_h": (-40, 120)
+}
+
+# 2026-01-01T00:00:00Z. A Pi without RTC that boots before its clock is set
+# reports a time before this.
+MINIMUM_TIME = 1767225600
+
+# adjtimex(2) state of a clock that is not synchronized
+TIME_ERROR = 5
+
+# large enough for struct timex on 32-bit and 64-bit Linux
+TIMEX_SIZE = 512
+
+# columns of records.tsv after the timestamp and the SenseHat methods reading
+# them
+CHANNELS = (
+    ("pressure", "get_pressure"),
+    ("temperature_p", "get_temperature_from_pressure"),
+    ("humidity", "get_humidity"),
+    ("temperature_h", "get_temperature_from_humidity")
+)
+
+# parameters of --simulate and their defaults
+SIMULATION = {
+    # mean air temperature in degrees Celsius
+    "temperature": 20.0,
+    # half the difference between daily maximum and minimum temperature
+    "swing": 5.0,
+    # mean relative humidity in percent
+    "humidity": 50.0,
+    # mean pressure in hPa
+    "pressure": 1013.25,
+    # pressure change in hPa per hour, counted from start
+    "trend": 0.0,
+    # Unix time at which the trend has not yet moved the pressure
+    "start": float(MINIMUM_TIME),
+    # scale of the sensor noise
+    "noise": 1.0,
+    # probability that a read returns an invalid value
+    "failure": 0.0
+}
+
+# columns of records.tsv written on every run. Optional columns follow them.
+COLUMNS = ("timestamp",) + tuple(name for name, _ in CHANNELS)
+
+# columns of --soc-channels
+SOC_COLUMNS = ("soc_temperature", "throttled")
+
+# columns of --monotonic
+MONOTONIC_COLUMNS = ("boot_id", "uptime")
+
+# columns of --mark-clock-sync
+CLOCK_COLUMNS = ("clock_synchronized",)
+
+# random identifier of the current boot
+BOOT_ID = "/proc/sys/kernel/random/boot_id"
+
+# command printing the throttling state of the SoC, e.g. throttled=0x50005
+GET_THROTTLED = ("vcgencmd", "get_throttled")
+
+# JSON Schema of each column of records.tsv except timestamp and tags. Empty
+# fields are null.
+COLUMN_SCHEMAS = {
+    "pressure": {
+        "type": ["number", "null"],
+        "description": "pressure from the LPS25H in hPa"
+    },
+    "temperature_p": {
+        "type": ["number", "null"],
+        "description": "temperature from the LPS25H in degrees Celsius"
+    },
+    "humidity": {
+        "type": ["number", "null"],
+        "description": "relative humidity from the HTS221 in percent"
+    },
+    "temperature_h": {
+        "type": ["number", "null"],
+        "description": "temperature from the HTS221 in degrees Celsius"
+    },
+    "soc_temperature": {
+        "type": ["number", "null"],
+        "description": "SoC temperature in degrees Celsius"
+    },
+    "throttled": {
+        "type": ["string", "null"],
+        "pattern": "^0x[0-9a-fA-F]+$",
+        "description": "bits of vcgencmd get_throttled"
+    },
+    "boot_id": {
+        "type": ["string", "null"],
+        "description": "boot ID from the kernel"
+    },
+    "uptime": {
+        "type": "number",
+        "description": "seconds since boot, including suspend"
+    },
+    "clock_synchronized": {
+        "enum": [0, 1, None],
+        "description": "whether the kernel reported the clock as synchronized"
+    }
+}
+
+def positive_int(value):
+    """
+    argparse type for integers greater than zero
+    """
+    number = int(value)
+
+    if number < 1:
+        raise argparse.ArgumentTypeError("must be at least 1")
+
+    return number
+
+def non_negative_int(value):
+    """
+    argparse type for integers not less than zero
+    """
+    number = int(value)
+
+    if number < 0:
+        raise argparse.ArgumentTypeError("must not be negative")
+
+    return number
+
+def non_negative_float(value):
+    """
+    argparse type for floats not less than zero
+    """
+    number = float(value)
+
+    if number < 0:
+        raise argparse.ArgumentTypeError("must not be negative")
+
+    return number
+
+def simulation(value):
+    """
+    argparse type for comma separated NAME=VALUE overrides of SIMULATION
+    """
+    parameters = dict(SIMULATION)
+
+    for item in filter(None, value.split(",")):
+        name, _, number = item.partition("=")
+
+        if name not in SIMULATION:
+            raise argparse.ArgumentTypeError(
+                "unknown parameter {}".format(name)
+            )
+
+        try:
+            parameters[name] = float(number)
+        except ValueError:
+            raise argparse.ArgumentTypeError(
+                "{} must be a number".format(name)
+            ) from None
+
+    return parameters
+
+def tags(value):
+    """
+    argparse type for comma separated NAME=VALUE tags, returned as pairs
+    """
+    pairs = []
+    reserved = COLUMNS + SOC_COLUMNS + MONOTONIC_COLUMNS + CLOCK_COLUMNS
+
+    for item in filter(None, value.split(",")):
+        name, separator, text = item.partition("=")
+
+        if not name or not separator:
+            raise argparse.ArgumentTypeError(
+                "{} is not NAME=VALUE".format(item)
+            )
+
+        if name in reserved or name in dict(pairs):
+            raise argparse.ArgumentTypeError(
+                "column {} already exists".format(name)
+            )
+
+        if any(character in item for character in "\t\n\r"):
+            raise argparse.ArgumentTypeError(
+                "tag {} contains a tab or newline".format(name)
+            )
+
+        pairs.append((name, text))
+
+    return tuple(pairs)
+
+def positive_float(value):
+    """
+    argparse type for floats greater than zero
+    """
+    number = float(value)
+
+    if number <= 0:
+        raise argparse.ArgumentTypeError("must be greater than 0")
+
+    return number
+
+def timezone(value):
+    """
+    argparse type for IANA time zone names
+    """
+    try:
+        return zoneinfo.ZoneInfo(value)
+    except (zoneinfo.ZoneInfoNotFoundError, ValueError):
+        raise argparse.ArgumentTypeError(
+            "unknown time zone {}".format(value)
+        ) from None
+
+class MonitorError(Exception):
+    """
+    an error ending the run with its exit_code
+    """
+    exit_code = 1
+
+class SensorNotFound(MonitorError):
+    """
+    the Sense HAT could not be opened
+    """
+    exit_code = 3
+
+class ReadError(MonitorError):
+    """
+    none of the channels could be read
+    """
+    exit_code = 4
+
+class OutputError(MonitorError):
+    """
+    the record could not be written
+    """
+    exit_code = 5
+
+class ClockError(MonitorError):
+    """
+    the system clock cannot be trusted for timestamps
+    """
+    exit_code = 6
+
+class LockError(MonitorError):
+    """
+    the bus lock file could not be locked
+    """
+    exit_code = 7
+
+class CheckFailed(MonitorError):
+    """
+    a diagnostic check failed
+    """
+    exit_code = 8
+
+class ChannelTimeout(Exception):
+    """
+    a channel did not respond in time
+    """
+
+def on_alarm(signum, frame):
+    """
+    SIGALRM handler interrupting a channel read
+    """
+    raise ChannelTimeout()
+
+def start_deadline(seconds):
+    """
+    monotonic time seconds from now, or None if seconds is 0
+    """
+    return time.monotonic() + seconds if seconds else None
+
+def time_left(timeout, deadline):
+    """
+    timeout shortened to the time left until deadline, or None once deadline
+    has passed. A timeout of 0 means none, as for setitimer.
+    """
+    if deadline is None:
+        return timeout
+
+    left = deadline - time.monotonic()
+
+    if left <= 0:
+        return None
+
+    return min(timeout, left) if timeout else left
+
+def retry(read, retries, backoff, jitter):
+    """
+    call read until it returns a valid value
+
+    sense_hat reports an invalid reading as 0 and a failed sensor
+    initialization as OSError. Both are retried up to retries times. The n-th
+    retry waits backoff * 2 ** (n - 1) seconds, stretched by a random fraction
+    of up to jitter. The last value or exception is passed on.
+    """
+    for attempt in range(retries + 1):
+        if attempt:
+            delay = (
+                backoff * 2 ** (attempt - 1) * (1 + random.uniform(0, jitter))
+            )
+            logging.debug("retry %d of %d in %.3f s", attempt, retries, delay)
+            time.sleep(delay)
+
+        try:
+            value = read()
+        except OSError:
+            if attempt == retries:
+                raise
+            continue
+
+        if value:
+            break
+
+    return value
+
+class Telemetry:
+    """
+    read statistics of a channel
+    """
+
+    def __init__(self):
+        self.samples = 0
+        self.reads = 0
+        self.reinitializations = 0
+        self.timeouts = 0
+        self.failures = 0
+        self.seconds = 0.0
+
+    def __str__(self):
+        latency = 1000 * self.seconds / self.samples if self.samples else 0
+
+        return (
+            "{} samples, {} reads, {} reinitializations, {} timeouts,"
+            " {} failures, {:.1f} ms per sample"
+        ).format(
+            self.samples,
+            self.reads,
+            self.reinitializations,
+            self.timeouts,
+            self.failures,
+            latency
+        )
+
+class SimulatedSenseHat:
+    """
+    stand-in for SenseHat generating plausible readings without hardware
+
+    Temperature follows a daily cosine peaking at 15:00 local time, humidity
+    moves against it, and pressure carries the semidiurnal atmospheric tide
+    plus a linear trend from a fixed start, so that consecutive runs continue
+    it. Every reading has Gaussian noise added.
+    """
+
+    def __init__(self, parameters):
+        self.parameters = parameters
+
+    def value(self, mean, spread):
+        """
+        mean with noise, or 0 like sense_hat for a simulated failure
+        """
+        if random.random() < self.parameters["failure"]:
+            return 0
+
+        return mean + random.gauss(0, spread * self.parameters["noise"])
+
+    @staticmethod
+    def hours():
+        """
+        local time of day in hours
+        """
+        now = time.localtime()
+
+        return now.tm_hour + now.tm_min / 60 + now.tm_sec / 3600
+
+    def temperature(self):
+        """
+        air temperature without noise
+        """
+        phase = 2 * math.pi * (self.hours() - 15) / 24
+
+        return (
+            self.parameters["temperature"]
+            + self.parameters["swing"] * math.cos(phase)
+        )
+
+    def get_pressure(self):
+        """
+        simulated LPS25H pressure
+        """
+        tide = 0.5 * math.cos(2 * math.pi * (self.hours() - 10) / 12)
+        trend = (
+            self.parameters["trend"]
+            * (time.time() - self.parameters["start"]) / 3600
+        )
+
+        return self.value(self.parameters["pressure"] + tide + trend, 0.02)
+
+    def get_temperature_from_pressure(self):
+        """
+        simulated LPS25H temperature
+        """
+        return self.value(self.temperature(), 0.05)
+
+    def get_humidity(self):
+        """
+        simulated HTS221 relative humidity
+        """
+        swing = self.temperature() - self.parameters["temperature"]
+        humidity = self.parameters["humidity"] - 3 * swing
+
+        return self.value(min(max(humidity, 0), 100), 0.3)
+
+    def get_temperature_from_humidity(self):
+        """
+        simulated HTS221 temperature
+        """
+        return self.value(self.temperature(), 0.1)
+
+    @staticmethod
+    def show_message(text):
+        """
+        log what the LED matrix would scroll
+        """
+        logging.info("LED matrix: %s", text)
+
+@contextlib.contextmanager
+def bus_lock(path, deadline=None):
+    """
+    hold an exclusive flock on path, or do nothing if path is None, waiting
+    for it until deadline
+    """
+    if path is None:
+        yield
+        return
+
+    try:
+        lock = open(path, "a")
+    except OSError as error:
+        raise LockError(
+            "cannot open bus lock {}: {}".format(path, error)
+        ) from error
+
+    with lock:
+        logging.debug("waiting for bus lock %s", path)
+        timeout = time_left(0, deadline)
+
+        if timeout is None:
+            raise LockError("deadline passed before locking {}".format(path))
+
+        try:
+            signal.setitimer(signal.ITIMER_REAL, timeout)
+            try:
+                fcntl.flock(lock, fcntl.LOCK_EX)
+            finally:
+                signal.setitimer(signal.ITIMER_REAL, 0)
+        except ChannelTimeout:
+            raise LockError(
+                "timed out waiting for bus lock {}".format(path)
+            ) from None
+
+        yield
+
+def open_sense_hat():
+    """
+    open the Sense HAT, raising SensorNotFound if it is missing
+    """
+    if SenseHat is None:
+        raise SensorNotFound("Sense HAT not found: sense_hat is not installed")
+
+    try:
+        return SenseHat()
+    except OSError as error:
+        raise SensorNotFound(
+            "Sense HAT not found: {}".format(error)
+        ) from error
+
+class Sensors:
+    """
+    Sense HAT sensors, reinitialized when a channel keeps failing
+    """
+
+    def __init__(self, open_sense=open_sense_hat):
+        self.open_sense = open_sense
+        self.sense = open_sense()
+        self.telemetry = {name: Telemetry() for name, _ in CHANNELS}
+
+    def reinitialize(self):
+        """
+        reopen the sensors so that sense_hat reruns their initialization
+        """
+        self.sense = self.open_sense()
+
+    def retry_channel(self, name, method, policy):
+        """
+        read a channel, reinitializing the sensors once if retries run out
+        """
+        telemetry = self.telemetry[name]
+
+        def read():
+            telemetry.reads += 1
+
+            try:
+                value = getattr(self.sense, method)()
+            except OSError as error:
+                logging.debug("%s: read failed: %s", name, error)
+                raise
+
+            logging.log(TRACE, "%s: read %s", name, value)
+
+            if not value:
+                logging.debug("%s: invalid reading", name)
+
+            return value
+
+        try:
+            value = retry(read, *policy)
+        except OSError:
+            value = 0
+
+        if not value:
+            logging.debug("%s: reinitializing sensors", name)
+            telemetry.reinitializations += 1
+
+            try:
+                self.reinitialize()
+            except SensorNotFound as error:
+                # the other channels may still read from the old instance
+                logging.warning("%s: %s", name, error)
+                return None
+
+            value = retry(read, *policy)
+
+        return value
+
+    def read_channel(self, name, method, policy, timeout):
+        """
+        read a channel, or None if it took longer than timeout seconds or kept
+        failing or returning invalid values
+        """
+        telemetry = self.telemetry[name]
+        telemetry.samples += 1
+        start = time.monotonic()
+
+        try:
+            signal.setitimer(signal.ITIMER_REAL, timeout)
+            try:
+                value = self.retry_channel(name, method, policy)
+            finally:
+                signal.setitimer(signal.ITIMER_REAL, 0)
+        except ChannelTimeout:
+            logging.warning("%s: timed out after %.1f s", name, timeout)
+            telemetry.timeouts += 1
+            return None
+        except OSError as error:
+            logging.warning("%s: %s", name, error)
+            telemetry.failures += 1
+            return None
+        finally:
+            telemetry.seconds += time.monotonic() - start
+
+        if not value:
+            logging.warning("%s: no valid reading", name)
+            telemetry.failures += 1
+            return None
+
+        return value
+
+    def read(self, policy, timeout, deadline=None):
+        """
+        read every channel once, retrying each according to policy, and
+        leaving the channels empty that deadline leaves no time for
+        """
+        values = []
+
+        for name, method in CHANNELS:
+            left = time_left(timeout, deadline)
+
+            if left is None:
+                logging.warning("%s: skipped, deadline passed", name)
+                values.append(None)
+            else:
+                values.append(self.read_channel(name, method, policy, left))
+
+        return tuple(values)
+
+def trimmed_mean(values):
+    """
+    mean of values with the lowest and the highest discarded, ignoring None
+    and the invalid reading 0
+    """
+    values = sorted(value for value in values if value)
+
+    if not values:
+        return None
+
+    if len(values) > 2:
+        values = values[1:-1]
+
+    return sum(values) / len(values)
+
+def columns(arguments):
+    """
+    names of the columns of records.tsv written with arguments
+    """
+    names = COLUMNS
+
+    if arguments.soc_channels:
+        names += SOC_COLUMNS
+
+    if arguments.monotonic:
+        names += MONOTONIC_COLUMNS
+
+    if arguments.mark_clock_sync:
+        names += CLOCK_COLUMNS
+
+    return names + tuple(name for name, _ in arguments.tags)
+
+def header(arguments):
+    """
+    first line of records.tsv naming the columns written with arguments
+    """
+    return "\t".join(columns(arguments))
+
+def schema(arguments):
+    """
+    JSON Schema of a record written with arguments, keyed by column name
+    """
+    if arguments.timestamp == "rfc3339":
+        timestamp = {"type": "string", "format": "date-time"}
+    else:
+        timestamp = {"type": "number", "description": "Unix time"}
+
+    properties = {"timestamp": timestamp}
+    properties.update(
+        (name, COLUMN_SCHEMAS[name]) for name in columns(arguments)[1:]
+        if name in COLUMN_SCHEMAS
+    )
+    properties.update(
+        (name, {"const": text, "description": "tag"})
+        for name, text in arguments.tags
+    )
+
+    return {
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "records.tsv record",
+        "description": "a line of records.tsv, with columns named by its"
+        " header and empty fields as null",
+        "type": "object",
+        "properties": properties,
+        "required": list(columns(arguments))
+    }
+
+def format_timestamp(timestamp, style, zone):
+    """
+    format a Unix timestamp for records.tsv as style
+    """
+    if style == "rfc3339":
+        return datetime.datetime.fromtimestamp(timestamp, zone).isoformat(
+            timespec="microseconds"
+        )
+
+    return str(timestamp)
+
+def format_value(value):
+    """
+    format a value for records.tsv, leaving missing values empty
+    """
+    return "" if value is None else str(value)
+
+def check_records(data, header, style):
+    """
+    whether data starts with header, raising OutputError if it starts with
+    another one or its first record has a timestamp other than style
+    """
+    data.seek(0)
+    found = data.readline().rstrip("\n")
+
+    if not found:
+        return False
+
+    if not found.startswith("timestamp\t"):
+        raise OutputError("records.tsv does not start with a header")
+
+    if found != header:
+        raise OutputError(
+            "records.tsv has columns {}, expected {}".format(
+                found.replace("\t", ", "), header.replace("\t", ", ")
+            )
+        )
+
+    record = data.readline()
+
+    if record:
+        try:
+            float(record.split("\t", 1)[0])
+        except ValueError:
+            recorded = "rfc3339"
+        else:
+            recorded = "unix"
+
+        if recorded != style:
+            raise OutputError(
+                "records.tsv has {} timestamps, not {}".format(
+                    recorded, style
+                )
+            )
+
+    return True
+
+def records_started(arguments):
+    """
+    whether records.tsv exists with its header, raising OutputError if a run
+    with arguments would refuse to append to it
+    """
+    try:
+        with open("records.tsv", newline="\n") as data:
+            return check_records(data, header(arguments), arguments.timestamp)
+    except FileNotFoundError:
+        return False
+    except OSError as error:
+        raise OutputError(
+            "cannot read records.tsv: {}".format(error)
+        ) from error
+
+def clock_synchronized():
+    """
+    whether the kernel reports the clock as synchronized, None if unknown
+    """
+    try:
+        adjtimex = ctypes.CDLL(None, use_errno=True).adjtimex
+    except (OSError, AttributeError):
+        return None
+
+    # a zeroed struct timex has modes 0 and only queries the clock
+    state = adjtimex(ctypes.create_string_buffer(TIMEX_SIZE))
+
+    if state == -1:
+        return None
+
+    return state != TIME_ERROR
+
+def check_clock(timestamp, require_sync):
+    """
+    raise ClockError unless timestamp can be trusted, returning whether the
+    clock is synchronized, None if unknown
+    """
+    if timestamp < MINIMUM_TIME:
+        raise ClockError(
+            "system clock is not set: {}".format(time.ctime(timestamp))
+        )
+
+    synchronized = clock_synchronized()
+
+    if synchronized is False:
+        if require_sync:
+            raise ClockError("system clock is not synchronized")
+
+        logging.warning("system clock is not synchronized")
+
+    return synchronized
+
+def read_soc_temperature():
+    """
+    SoC temperature in degrees Celsius, or None if it cannot be read
+    """
+    try:
+        with open(THERMAL_ZONE) as zone:
+            return int(zone.read()) / 1000
+    except (OSError, ValueError) as error:
+        logging.warning("SoC temperature: %s", error)
+        return None
+
+def read_boot_id():
+    """
+    identifier of the current boot, or None if it cannot be read
+    """
+    try:
+        with open(BOOT_ID) as boot_id:
+            return boot_id.read().strip()
+    except OSError as error:
+        logging.warning("boot ID: %s", error)
+        return None
+
+def read_throttled():
+    """
+    throttling bits of the SoC from vcgencmd, or None if they cannot be read
+    """
+    try:
+        output = subprocess.run(
+            GET_THROTTLED,
+            capture_output=True,
+            check=True,
+            text=True,
+            timeout=5
+        ).stdout
+    except (OSError, subprocess.SubprocessError) as error:
+        logging.warning("throttling state: %s", error)
+        return None
+
+    name, _, bits = output.strip().partition("=")
+
+    if name != "throttled" or not bits:
+        logging.warning("throttling state: unexpected output %r", output)
+        return None
+
+    return bits
+
+def compensate(temperature, soc_temperature, factor):
+    """
+    air temperature from a sensor heated by the SoC beneath it
+
+    The sensor reads between the air and the SoC temperature, so the
+    difference to the SoC, scaled down by factor, is taken off the reading.
+    Without the SoC temperature the reading cannot be corrected and None is
+    returned, so that a column never mixes corrected and raw values.
+    """
+    if temperature is None or soc_temperature is None:
+        return None
+
+    return temperature - (soc_temperature - temperature) / factor
+
+def display_text(result):
+    """
+    short summary of a record for the LED matrix
+    """
+    _, pressure, temperature_p, humidity, temperature_h = result
+
+    # the HTS221 temperature, like SenseHat.get_temperature
+    temperature = temperature_h if temperature_h is not None else temperature_p
+
+    parts = []
+
+    if temperature is not None:
+        parts.append("{:.1f}C".format(temperature))
+
+    if humidity is not None:
+        parts.append("{:.0f}%".format(humidity))
+
+    if pressure is not None:
+        parts.append("{:.0f}hPa".format(pressure))
+
+    return " ".join(parts)
+
+def parse_boolean(value):
+    """
+    truth value of an environment variable
+    """
+    if value.lower() in ("1", "true", "yes", "on"):
+        return True
+
+    if value.lower() in ("0", "false", "no", "off"):
+        return False
+
+    raise argparse.ArgumentTypeError("must be true or false")
+
+def add_option(options, group, *names, **settings):
+    """
+    add an option to the parser or group and append its action to options
+    """
+    options.append(group.add_argument(*names, **settings))
+
+def environment_name(action):
+    """
+    name of the environment variable setting the option of action
+    """
+    return "ENV_MONITOR_" + action.dest.upper()
+
+def environment_defaults(parser, options):
+    """
+    option defaults from ENV_MONITOR_<OPTION> environment variables
+
+    An empty variable counts as unset, except for options with an optional
+    value, which it enables without one.
+    """
+    defaults = {}
+
+    for action in options:
+        name = environment_name(action)
+        value = os.environ.get(name)
+
+        if value is None or (value == "" and action.nargs != "?"):
+            continue
+
+        try:
+            if action.nargs == 0 and action.const is True:
+                defaults[action.dest] = parse_boolean(value)
+            elif action.nargs == 0:
+                defaults[action.dest] = non_negative_int(value)
+            elif action.nargs == "?" and value == "":
+                defaults[action.dest] = action.type(action.const)
+            else:
+                if action.choices and value not in action.choices:
+                    raise argparse.ArgumentTypeError(
+                        "must be one of " + ", ".join(action.choices)
+                    )
+
+                defaults[action.dest] = (
+                    action.type(value) if action.type else value
+                )
+        except argparse.ArgumentTypeError as error:
+            parser.error("{}: {}".format(name, error))
+        except ValueError:
+            parser.error("{}: invalid value {}".format(name, value))
+
+    return defaults
+
+def exclusive_defaults(parser, arguments, group, environment):
+    """
+    set the options of a mutually exclusive group from the environment
+
+    An option of group on the command line overrides the environment for the
+    whole group. Two options of group set by the environment are an error.
+    """
+    for action in group:
+        if getattr(arguments, action.dest) != action.default:
+            return
+
+    enabled = [
+        action for action in group
+        if environment.get(action.dest, action.default) != action.default
+    ]
+
+    if len(enabled) > 1:
+        parser.error(
+            "{} not allowed with {}".format(
+                environment_name(enabled[1]), environment_name(enabled[0])
+            )
+        )
+
+    for action in enabled:
+        setattr(arguments, action.dest, environment[action.dest])
+
+def parse_arguments(parser, options, groups, args=None):
+    """
+    parse args or the command line with defaults from the environment
+
+    options are the actions of the parser itself, groups lists of the actions
+    of each mutually exclusive group.
+    """
+    environment = environment_defaults(
+        parser, options + [action for group in groups for action in group]
+    )
+
+    parser.set_defaults(
+        **{
+            action.dest: environment[action.dest]
+            for action in options if action.dest in environment
+        }
+    )
+
+    arguments = parser.parse_args(args)
+
+    for group in groups:
+        exclusive_defaults(parser, arguments, group, environment)
+
+    return arguments
+
+def open_sensors(arguments):
+    """
+    Sensors of the Sense HAT, or simulated ones if --simulate was given
+    """
+    if arguments.simulate is None:
+        return Sensors()
+
+    return Sensors(lambda: SimulatedSenseHat(arguments.simulate))
+
+def diagnose(arguments, policy):
+    """
+    yield the name, outcome, and detail of each diagnostic check
+    """
+    if SenseHat is None:
+        yield (
+            "sense_hat",
+            False,
+            "not installed: pip install -r requirements.txt"
+        )
+    else:
+        yield "sense_hat", True, "installed"
+
+    if os.path.exists(I2C_BUS) or os.path.isdir("/sys/module/i2c_dev"):
+        yield "i2c-dev", True, "loaded"
+    else:
+        yield "i2c-dev", False, "not loaded: add i2c-dev to /etc/modules"
+
+    if not os.path.exists(I2C_BUS):
+        yield (
+            "I2C bus",
+            False,
+            "{} missing: enable I2C with raspi-config".format(I2C_BUS)
+        )
+    elif not os.access(I2C_BUS, os.R_OK | os.W_OK):
+        yield (
+            "I2C bus",
+            False,
+            "{} not accessible: add the user to the i2c group".format(I2C_BUS)
+        )
+    else:
+        yield "I2C bus", True, "{} accessible".format(I2C_BUS)
+
+    names = []
+
+    for path in glob.glob("/sys/class/graphics/fb*/name"):
+        with open(path) as name:
+            names.append(name.read().strip())
+
+    if FRAMEBUFFER_NAME in names:
+        yield "LED matrix", True, "{} found".format(FRAMEBUFFER_NAME)
+    else:
+        yield (
+            "LED matrix",
+            False,
+            "{} not found: check that the HAT is seated and its overlay is"
+            " loaded".format(FRAMEBUFFER_NAME)
+        )
+
+    deadline = start_deadline(arguments.deadline)
+
+    try:
+        with bus_lock(arguments.bus_lock, deadline):
+            values = open_sensors(arguments).read(
+                policy, arguments.timeout, deadline
+            )
+    except MonitorError as error:
+        yield "sensors", False, str(error)
+    else:
+        failed = [
+            name for (name, _), value in zip(CHANNELS, values) if not value
+        ]
+
+        if failed:
+            yield "sensors", False, "no valid reading: " + ", ".join(failed)
+        else:
+            yield "sensors", True, "all channels read"
+
+    synchronized = clock_synchronized()
+
+    if time.time() < MINIMUM_TIME:
+        yield "clock", False, "not set: enable systemd-timesyncd or chrony"
+    elif synchronized is None:
+        yield "clock", False, "synchronization state unknown"
+    elif not synchronized:
+        yield (
+            "clock",
+            False,
+            "not synchronized: check systemd-timesyncd or chrony"
+        )
+    else:
+        yield "clock", True, "synchronized"
+
+    directory = os.getcwd()
+    target = "records.tsv" if os.path.exists("records.tsv") else directory
+
+    if not os.access(target, os.W_OK):
+        yield (
+            "output",
+            False,
+            "{} not writable".format(os.path.abspath(target))
+        )
+    else:
+        free = shutil.disk_usage(directory).free
+
+        yield (
+            "output",
+            free >= MINIMUM_FREE_SPACE,
+            "{} MiB free in {}".format(free // (1024 * 1024), directory)
+        )
+
+    try:
+        started = records_started(arguments)
+    except OutputError as error:
+        yield "records", False, "{}: move it aside".format(error)
+    else:
+        yield (
+            "records",
+            True,
+            "header and timestamps match" if started
+            else "records.tsv will be started"
+        )
+
+def report(checks):
+    """
+    print the outcome of every check, raising CheckFailed on failures
+    """
+    failures = 0
+    total = 0
+
+    for name, passed, detail in checks:
+        print("{} {}: {}".format("PASS" if passed else "FAIL", name, detail))
+        total += 1
+        failures += not passed
+
+    if failures:
+        raise CheckFailed("{} of {} checks failed".format(failures, total))
+
+def bounds_checks(values):
+    """
+    yield the name, outcome, and detail of the bounds check of each channel
+    """
+    for (name, _), value in zip(CHANNELS, values):
+        low, high = SELF_TEST_BOUNDS[name]
+        expected = "expected {} to {}".format(low, high)
+
+        # None is a channel left empty, 0 an invalid reading from sense_hat
+        if not value:
+            yield name, False, "no valid reading, " + expected
+        else:
+            yield name, low <= value <= high, "{}, {}".format(value, expected)
+
+def self_test(arguments, policy):
+    """
+    read every channel once and check it against SELF_TEST_BOUNDS
+    """
+    deadline = start_deadline(arguments.deadline)
+
+    with bus_lock(arguments.bus_lock, deadline):
+        values = open_sensors(arguments).read(
+            policy, arguments.timeout, deadline
+        )
+
+    report(bounds_checks(values))
+
+def log_level(parser, arguments):
+    """
+    log level from -v and -q, falling back to ENV_MONITOR_LOG
+    """
+    if arguments.quiet:
+        return logging.WARNING
+
+    if arguments.verbose:
+        return VERBOSITY[min(arguments.verbose, len(VERBOSITY) - 1)]
+
+    name = os.environ.get("ENV_MONITOR_LOG", "INFO").upper()
+    level = logging.getLevelName(name)
+
+    if not isinstance(level, int):
+        parser.error("ENV_MONITOR_LOG: unknown log level {}".format(name))
+
+    return level
 
 def main():
     """
     main function
     """
 
-    sense = SenseHat()
-
     parser = argparse.ArgumentParser(description="write sensor value to file")
 
-    parser.add_argument(
+    options = []
+
+    mode = parser.add_mutually_exclusive_group()
+    modes = []
+
+    add_option(
+        modes,
+        mode,
         "--init",
         action="store_true",
         help="initialize sensors. Data are discarded."
     )
 
-    arguments = parser.parse_args()
+    add_option(
+        modes,
+        mode,
+        "--doctor",
+        action="store_true",
+        help="check the I2C bus, the Sense HAT, the clock, and the output"
+        " directory, and print what to fix."
+    )
+
+    add_option(
+        modes,
+        mode,
+        "--self-test",
+        action="store_true",
+        help="instead of recording, read every channel once and check that it"
+        " is within plausible bounds."
+    )
+
+    add_option(
+        modes,
+        mode,
+        "--dry-run",
+        action="store_true",
+        help="print the record instead of writing it."
+    )
+
+    add_option(
+        modes,
+        mode,
+        "--schema",
+        action="store_true",
+        help="instead of recording, print the JSON Schema of the records the"
+        " other options would write."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--samples-per-reading",
+        type=positive_int,
+        default=1,
+        metavar="N",
+        help="take N samples and record their trimmed mean. Default: 1."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--retries",
+        type=non_negative_int,
+        default=2,
+        metavar="N",
+        help="retry a failed sensor read up to N times. Default: 2."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--retry-backoff",
+        type=non_negative_float,
+        default=0.1,
+        metavar="SECONDS",
+        help="delay before the first retry, doubled for each next one."
+        " Default: 0.1."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--retry-jitter",
+        type=non_negative_float,
+        default=0.5,
+        metavar="FRACTION",
+        help="stretch each retry delay by a random fraction up to FRACTION."
+        " Default: 0.5."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--timeout",
+        type=non_negative_float,
+        default=5,
+        metavar="SECONDS",
+        help="leave a channel empty if reading it takes longer than SECONDS."
+        " 0 disables the timeout. Default: 5."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--deadline",
+        type=non_negative_float,
+        default=30,
+        metavar="SECONDS",
+        help="stop waiting for the bus lock and reading samples after SECONDS"
+        " in total. 0 disables the deadline. Default: 30."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--bus-lock",
+        metavar="FILE",
+        help="hold an exclusive lock on FILE while the sensors are read, e.g."
+        " /run/lock/i2c-1.lock."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--cpu-compensation",
+        type=positive_float,
+        metavar="FACTOR",
+        help="correct the temperatures for heat from the SoC: T - (T_SoC - T)"
+        " / FACTOR."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--soc-channels",
+        action="store_true",
+        help="also record the SoC temperature and the throttling bits of"
+        " vcgencmd get_throttled."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--timestamp",
+        choices=("unix", "rfc3339"),
+        default="unix",
+        help="record timestamps as Unix time or as RFC 3339 date and time."
+        " Default: unix."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--timezone",
+        type=timezone,
+        metavar="ZONE",
+        help="time zone of RFC 3339 timestamps, e.g. Europe/Berlin."
+        " Default: UTC."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--monotonic",
+        action="store_true",
+        help="also record the boot ID and the time since boot, which are not"
+        " affected when the clock is set."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--require-clock-sync",
+        action="store_true",
+        help="write nothing unless the system clock is synchronized."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--mark-clock-sync",
+        action="store_true",
+        help="also record whether the system clock was synchronized."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--tags",
+        type=tags,
+        default=(),
+        metavar="NAME=VALUE,...",
+        help="add a column NAME holding VALUE to every record, e.g."
+        " site=roof,host=pi1."
+    )
 
-    result = (
-        time.time(),
-        sense.get_pressure(),
-        sense.get_temperature_from_pressure(),
-        sense.get_humidity(),
-        sense.get_temperature_from_humidity()
+    add_option(
+        options,
+        parser,
+        "--display",
+        action="store_true",
+        help="scroll the recorded values across the LED matrix."
+    )
+
+    add_option(
+        options,
+        parser,
+        "--simulate",
+        type=simulation,
+        nargs="?",
+        const="",
+        metavar="NAME=VALUE,...",
+        help="read simulated sensors instead of the Sense HAT. Parameters: "
+        + ", ".join(SIMULATION) + "."
     )
 
+    verbosity = parser.add_mutually_exclusive_group()
+    verbosities = []
+
+    add_option(
+        verbosities,
+        verbosity,
+        "-v",
+        "--verbose",
+        action="count",
+        default=0,
+        help="log sensor reads, retries, and writes. Repeat to also log every"
+        " raw value."
+    )
+
+    add_option(
+        verbosities,
+        verbosity,
+        "-q",
+        "--quiet",
+        action="store_true",
+        help="log only warnings and errors."
+    )
+
+    arguments = parse_arguments(parser, options, (modes, verbosities))
+
+    if arguments.timezone and arguments.timestamp != "rfc3339":
+        parser.error("--timezone requires --timestamp rfc3339")
+
+    policy = (
+        arguments.retries,
+        arguments.retry_backoff,
+        arguments.retry_jitter
+    )
+
+    logging.basicConfig(
+        format="%(levelname)s: %(message)s",
+        level=log_level(parser, arguments)
+    )
+
+    signal.signal(signal.SIGALRM, on_alarm)
+
+    if arguments.doctor:
+        report(diagnose(arguments, policy))
+        return
+
+    if arguments.self_test:
+        self_test(arguments, policy)
+        return
+
+    if arguments.schema:
+        print(json.dumps(schema(arguments), indent=2))
+        return
+
+    deadline = start_deadline(arguments.deadline)
+
+    with bus_lock(arguments.bus_lock, deadline):
+        sensors = open_sensors(arguments)
+
+        timestamp = time.time()
+        # seconds since boot including suspend, unaffected by clock steps
+        uptime = time.clock_gettime(time.CLOCK_BOOTTIME)
+
+        samples = []
+
+        for i in range(arguments.samples_per_reading):
+            if i:
+                if (
+                    deadline is not None
+                    and time.monotonic() + SAMPLE_DELAY >= deadline
+                ):
+                    logging.warning(
+                        "deadline passed after %d of %d samples",
+                        i,
+                        arguments.samples_per_reading
+                    )
+                    break
+
+                time.sleep(SAMPLE_DELAY)
+
+            samples.append(
+                sensors.read(policy, arguments.timeout, deadline)
+            )
+
+    for name, telemetry in sensors.telemetry.items():
+        logging.info("%s: %s", name, telemetry)
+
+    result = (timestamp,) + tuple(
+        trimmed_mean(channel) for channel in zip(*samples)
+    )
+
+    if all(value is None for value in result[1:]):
+        raise ReadError("no channel could be read")
+
+    if arguments.cpu_compensation or arguments.soc_channels:
+        soc_temperature = read_soc_temperature()
+        logging.debug("SoC temperature: %s", soc_temperature)
+
+    if arguments.cpu_compensation:
+        if soc_temperature is None:
+            logging.warning("temperatures left empty: no SoC temperature")
+
+        timestamp, pressure, temperature_p, humidity, temperature_h = result
+
+        result = (
+            timestamp,
+            pressure,
+            compensate(
+                temperature_p, soc_temperature, arguments.cpu_compensation
+            ),
+            humidity,
+            compensate(
+                temperature_h, soc_temperature, arguments.cpu_compensation
+            )
+        )
+
     if arguments.init:
         pass
     else:
-        with open("records.tsv", "a", newline="\n") as data:
-           data.write("{}\t{}\t{}\t{}\t{}\n".format(*result)) 
+        synchronized = check_clock(timestamp, arguments.require_clock_sync)
+
+        extras = ()
+
+        if arguments.soc_channels:
+            extras += (soc_temperature, read_throttled())
+
+        if arguments.monotonic:
+            extras += (read_boot_id(), uptime)
+
+        if arguments.mark_clock_sync:
+            extras += (None if synchronized is None else int(synchronized),)
+
+        extras += tuple(text for _, text in arguments.tags)
+
+        line = "\t".join(
+            (
+                format_timestamp(
+                    timestamp,
+                    arguments.timestamp,
+                    arguments.timezone or datetime.timezone.utc
+                ),
+            )
+            + tuple(format_value(value) for value in result[1:] + extras)
+        )
+
+        if arguments.dry_run:
+            if not records_started(arguments):
+                print(
+                    "would start {} with: {}".format(
+                        os.path.abspath("records.tsv"), header(arguments)
+                    )
+                )
+
+            print(
+                "would append to {}: {}".format(
+                    os.path.abspath("records.tsv"), line
+                )
+            )
+        else:
+            logging.debug("appending to records.tsv: %s", line)
+
+            try:
+                with open("records.tsv", "a+", newline="\n") as data:
+                    fcntl.flock(data, fcntl.LOCK_EX)
+
+                    if not check_records(
+                        data, header(arguments), arguments.timestamp
+                    ):
+                        data.write(header(arguments) + "\n")
+
+                    data.write(line + "\n")
+            except OSError as error:
+                raise OutputError(
+                    "cannot write records.tsv: {}".format(error)
+                ) from error
+
+        if arguments.display and not arguments.dry_run:
+            try:
+                sensors.sense.show_message(display_text(result))
+            except OSError as error:
+                logging.warning("LED matrix: %s", error)
 
 if __name__ == "__main__":
-    main()
+    try:
+        main()
+    except MonitorError as error:
+        logging.error("%s", error)
+        sys.exit(error.exit_code)
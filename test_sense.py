@@ -0,0 +1,376 @@
+"""
+test_sense.py: Tests of sense.py without a Sense HAT.
+"""
+import io
+import os
+import time
+import argparse
+import unittest
+import subprocess
+from unittest import mock
+
+import sense
+
+NO_RETRY = (0, 0, 0)
+
+class StubSenseHat:
+    """
+    SenseHat returning the same value on every channel
+    """
+
+    def __init__(self, value):
+        self.value = value
+
+    def get_pressure(self):
+        return self.value
+
+    def get_temperature_from_pressure(self):
+        return self.value
+
+    def get_humidity(self):
+        return self.value
+
+    def get_temperature_from_humidity(self):
+        return self.value
+
+class TestRetry(unittest.TestCase):
+
+    def test_returns_first_valid_value(self):
+        values = iter((0, 0, 42))
+
+        self.assertEqual(sense.retry(lambda: next(values), 2, 0, 0), 42)
+
+    def test_returns_last_invalid_value(self):
+        self.assertEqual(sense.retry(lambda: 0, 2, 0, 0), 0)
+
+    def test_raises_last_error(self):
+        def read():
+            raise OSError("Init Failed")
+
+        with self.assertRaises(OSError):
+            sense.retry(read, 2, 0, 0)
+
+class TestTrimmedMean(unittest.TestCase):
+
+    def test_ignores_missing_and_invalid_values(self):
+        self.assertEqual(sense.trimmed_mean((None, 0, 1012.0, 0)), 1012.0)
+
+    def test_discards_lowest_and_highest(self):
+        self.assertEqual(sense.trimmed_mean((1.0, 2.0, 3.0, 100.0)), 2.5)
+
+    def test_no_valid_values(self):
+        self.assertIsNone(sense.trimmed_mean((None, 0)))
+
+class TestSensors(unittest.TestCase):
+
+    def test_valid_reading(self):
+        sensors = sense.Sensors(lambda: StubSenseHat(1013.0))
+
+        self.assertEqual(sensors.read(NO_RETRY, 0), (1013.0,) * 4)
+
+    def test_invalid_reading_is_empty(self):
+        sensors = sense.Sensors(lambda: StubSenseHat(0))
+
+        with self.assertLogs(level="WARNING"):
+            self.assertEqual(sensors.read(NO_RETRY, 0), (None,) * 4)
+
+        telemetry = sensors.telemetry["pressure"]
+        self.assertEqual(telemetry.reinitializations, 1)
+        self.assertEqual(telemetry.failures, 1)
+
+    def test_failed_reinitialization_is_empty(self):
+        opened = []
+
+        def open_sense():
+            if opened:
+                raise sense.SensorNotFound("Sense HAT not found")
+            opened.append(StubSenseHat(0))
+            return opened[0]
+
+        sensors = sense.Sensors(open_sense)
+
+        with self.assertLogs(level="WARNING"):
+            self.assertEqual(sensors.read(NO_RETRY, 0), (None,) * 4)
+
+        self.assertEqual(sensors.telemetry["humidity"].failures, 1)
+
+    def test_passed_deadline_skips_channels(self):
+        sensors = sense.Sensors(lambda: StubSenseHat(1013.0))
+
+        with self.assertLogs(level="WARNING"):
+            values = sensors.read(NO_RETRY, 0, time.monotonic() - 1)
+
+        self.assertEqual(values, (None,) * 4)
+        self.assertEqual(sensors.telemetry["pressure"].reads, 0)
+
+class TestEnvironment(unittest.TestCase):
+
+    def setUp(self):
+        self.parser = argparse.ArgumentParser()
+        self.options = []
+        sense.add_option(
+            self.options,
+            self.parser,
+            "--retries",
+            type=sense.non_negative_int,
+            default=2
+        )
+        sense.add_option(
+            self.options, self.parser, "--dry-run", action="store_true"
+        )
+        sense.add_option(
+            self.options,
+            self.parser,
+            "--simulate",
+            type=sense.simulation,
+            nargs="?",
+            const=""
+        )
+
+        group = self.parser.add_mutually_exclusive_group()
+        self.verbosities = []
+        sense.add_option(
+            self.verbosities,
+            group,
+            "-v",
+            "--verbose",
+            action="count",
+            default=0
+        )
+        sense.add_option(
+            self.verbosities, group, "-q", "--quiet", action="store_true"
+        )
+
+    def defaults(self, **environment):
+        with mock.patch.dict(os.environ, environment, clear=True):
+            return sense.environment_defaults(
+                self.parser, self.options + self.verbosities
+            )
+
+    def parse(self, args, **environment):
+        with mock.patch.dict(os.environ, environment, clear=True):
+            return sense.parse_arguments(
+                self.parser, self.options, (self.verbosities,), args
+            )
+
+    def test_values(self):
+        self.assertEqual(
+            self.defaults(
+                ENV_MONITOR_RETRIES="5",
+                ENV_MONITOR_DRY_RUN="yes",
+                ENV_MONITOR_VERBOSE="2"
+            ),
+            {"retries": 5, "dry_run": True, "verbose": 2}
+        )
+
+    def test_empty_variables(self):
+        self.assertEqual(
+            self.defaults(ENV_MONITOR_RETRIES="", ENV_MONITOR_SIMULATE=""),
+            {"simulate": sense.SIMULATION}
+        )
+
+    def test_invalid_value(self):
+        with mock.patch("sys.stderr", io.StringIO()):
+            with self.assertRaises(SystemExit):
+                self.defaults(ENV_MONITOR_RETRIES="-1")
+
+    def test_command_line_overrides_environment(self):
+        arguments = self.parse(["--retries", "2"], ENV_MONITOR_RETRIES="5")
+
+        self.assertEqual(arguments.retries, 2)
+
+    def test_exclusive_variables(self):
+        with mock.patch("sys.stderr", io.StringIO()) as stderr:
+            with self.assertRaises(SystemExit):
+                self.parse(
+                    [], ENV_MONITOR_QUIET="true", ENV_MONITOR_VERBOSE="1"
+                )
+
+        self.assertIn("ENV_MONITOR_QUIET", stderr.getvalue())
+
+    def test_exclusive_command_line_overrides_environment(self):
+        arguments = self.parse(["-v"], ENV_MONITOR_QUIET="true")
+
+        self.assertEqual(arguments.verbose, 1)
+        self.assertFalse(arguments.quiet)
+
+    def test_exclusive_variable(self):
+        arguments = self.parse([], ENV_MONITOR_QUIET="true")
+
+        self.assertTrue(arguments.quiet)
+
+class TestSimulatedSenseHat(unittest.TestCase):
+
+    def pressure(self, trend, now):
+        parameters = dict(sense.SIMULATION, noise=0, trend=trend, start=0)
+
+        with mock.patch("time.time", return_value=now):
+            return sense.SimulatedSenseHat(parameters).get_pressure()
+
+    def test_trend_counts_from_start(self):
+        for now in (3600, 90000):
+            self.assertAlmostEqual(
+                self.pressure(1, now) - self.pressure(0, now),
+                now / 3600,
+                places=3
+            )
+
+class TestCompensate(unittest.TestCase):
+
+    def test_takes_off_soc_heating(self):
+        self.assertEqual(sense.compensate(30.0, 50.0, 2), 20.0)
+
+    def test_missing_soc_temperature(self):
+        self.assertIsNone(sense.compensate(30.0, None, 2))
+
+    def test_missing_temperature(self):
+        self.assertIsNone(sense.compensate(None, 50.0, 2))
+
+class TestReadThrottled(unittest.TestCase):
+
+    def throttled(self, output):
+        result = subprocess.CompletedProcess((), 0, stdout=output)
+
+        with mock.patch("subprocess.run", return_value=result):
+            return sense.read_throttled()
+
+    def test_bits(self):
+        self.assertEqual(self.throttled("throttled=0x50005\n"), "0x50005")
+
+    def test_unexpected_output(self):
+        with self.assertLogs(level="WARNING"):
+            self.assertIsNone(self.throttled("VCHI initialization failed\n"))
+
+    def test_missing_vcgencmd(self):
+        with mock.patch("subprocess.run", side_effect=FileNotFoundError()):
+            with self.assertLogs(level="WARNING"):
+                self.assertIsNone(sense.read_throttled())
+
+class TestCheckClock(unittest.TestCase):
+
+    def check(self, synchronized, require_sync):
+        with mock.patch("sense.clock_synchronized", return_value=synchronized):
+            return sense.check_clock(sense.MINIMUM_TIME, require_sync)
+
+    def test_returns_state(self):
+        self.assertTrue(self.check(True, False))
+        self.assertIsNone(self.check(None, True))
+
+        with self.assertLogs(level="WARNING"):
+            self.assertFalse(self.check(False, False))
+
+    def test_requires_sync(self):
+        with self.assertRaises(sense.ClockError):
+            self.check(False, True)
+
+    def test_clock_not_set(self):
+        with self.assertRaises(sense.ClockError):
+            sense.check_clock(0, False)
+
+class TestFormatTimestamp(unittest.TestCase):
+
+    def test_unix(self):
+        self.assertEqual(
+            sense.format_timestamp(1767225600.5, "unix", None), "1767225600.5"
+        )
+
+    def test_rfc3339_in_zone(self):
+        self.assertEqual(
+            sense.format_timestamp(
+                1767225600.5, "rfc3339", sense.timezone("Europe/Berlin")
+            ),
+            "2026-01-01T01:00:00.500000+01:00"
+        )
+
+class TestBoundsChecks(unittest.TestCase):
+
+    def test_outcomes(self):
+        checks = list(sense.bounds_checks((1013.0, 200.0, 0, None)))
+
+        self.assertEqual(
+            [(name, passed) for name, passed, _ in checks],
+            [
+                ("pressure", True),
+                ("temperature_p", False),
+                ("humidity", False),
+                ("temperature_h", False)
+            ]
+        )
+        self.assertIn("no valid reading", checks[2][2])
+
+class TestTags(unittest.TestCase):
+
+    def test_pairs(self):
+        self.assertEqual(
+            sense.tags("site=roof,host=pi1,empty="),
+            (("site", "roof"), ("host", "pi1"), ("empty", ""))
+        )
+
+    def test_invalid_tags(self):
+        for value in (
+            "site",
+            "=roof",
+            "pressure=1",
+            "uptime=1",
+            "site=roof,site=attic",
+            "site=ro\tof"
+        ):
+            with self.assertRaises(argparse.ArgumentTypeError, msg=value):
+                sense.tags(value)
+
+class TestSchema(unittest.TestCase):
+
+    def test_follows_columns(self):
+        arguments = argparse.Namespace(
+            timestamp="rfc3339",
+            soc_channels=True,
+            monotonic=False,
+            mark_clock_sync=False,
+            tags=(("site", "roof"),)
+        )
+        schema = sense.schema(arguments)
+
+        self.assertEqual(list(schema["properties"]), schema["required"])
+        self.assertEqual(
+            tuple(schema["required"]), sense.columns(arguments)
+        )
+        self.assertEqual(
+            schema["properties"]["timestamp"]["format"], "date-time"
+        )
+        self.assertEqual(schema["properties"]["site"]["const"], "roof")
+
+class TestRecords(unittest.TestCase):
+
+    def setUp(self):
+        self.header = "\t".join(sense.COLUMNS)
+
+    def test_empty_file(self):
+        self.assertFalse(
+            sense.check_records(io.StringIO(""), self.header, "unix")
+        )
+
+    def test_matching_file(self):
+        data = io.StringIO(self.header + "\n1767225600.0\t1013\t20\t50\t20\n")
+
+        self.assertTrue(sense.check_records(data, self.header, "unix"))
+
+    def test_other_columns(self):
+        data = io.StringIO(self.header + "\tsite\n")
+
+        with self.assertRaises(sense.OutputError):
+            sense.check_records(data, self.header, "unix")
+
+    def test_other_timestamps(self):
+        data = io.StringIO(self.header + "\n1767225600.0\t1013\t20\t50\t20\n")
+
+        with self.assertRaises(sense.OutputError):
+            sense.check_records(data, self.header, "rfc3339")
+
+    def test_missing_header(self):
+        data = io.StringIO("1767225600.0\t1013\t20\t50\t20\n")
+
+        with self.assertRaises(sense.OutputError):
+            sense.check_records(data, self.header, "unix")
+
+if __name__ == "__main__":
+    unittest.main()